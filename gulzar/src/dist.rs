@@ -1,6 +1,6 @@
 use core::cmp::Ordering;
-use harfbuzz_wasm::debug;
 use kurbo::{Affine, BezPath, ParamCurve, ParamCurveNearest, PathSeg};
+use std::collections::HashMap;
 
 pub fn _determine_kern(
     left_paths: &[BezPath],
@@ -50,6 +50,78 @@ pub fn _determine_kern(
     kern
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct KernKey {
+    left_codepoint: u32,
+    right_codepoint: u32,
+    target_distance_bits: u32,
+    scale_factor_bits: u32,
+}
+
+impl KernKey {
+    fn new(
+        left_codepoint: u32,
+        right_codepoint: u32,
+        target_distance: f32,
+        scale_factor: f32,
+    ) -> Self {
+        Self {
+            left_codepoint,
+            right_codepoint,
+            target_distance_bits: target_distance.to_bits(),
+            scale_factor_bits: scale_factor.to_bits(),
+        }
+    }
+}
+
+// Double-buffered kern cache, in the same vein as an editor's text-layout
+// cache: entries live in `current` for the run that touched them and in
+// `previous` for one run after that, then `finish_run` rotates the maps
+// so anything nobody asked for this run quietly falls out of both.
+#[derive(Debug, Default)]
+pub struct KernCache {
+    current: HashMap<KernKey, f32>,
+    previous: HashMap<KernKey, f32>,
+}
+
+impl KernCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn determine_kern(
+        &mut self,
+        left_codepoint: u32,
+        right_codepoint: u32,
+        left_paths: &[BezPath],
+        right_paths: &[BezPath],
+        target_distance: f32,
+        max_tuck: f32,
+        scale_factor: f32,
+    ) -> f32 {
+        let key = KernKey::new(left_codepoint, right_codepoint, target_distance, scale_factor);
+        if let Some(&kern) = self.current.get(&key) {
+            return kern;
+        }
+        if let Some(kern) = self.previous.remove(&key) {
+            self.current.insert(key, kern);
+            return kern;
+        }
+        let kern =
+            _determine_kern(left_paths, right_paths, target_distance, max_tuck, scale_factor);
+        self.current.insert(key, kern);
+        kern
+    }
+
+    // Call once per shaping run, after the last `determine_kern` lookup,
+    // so entries nobody touched this run are evicted instead of leaking
+    // forever.
+    pub fn finish_run(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+
 pub fn path_distance(left_paths: &[BezPath], right_paths: &[BezPath]) -> Option<f32> {
     let mut min_distance: Option<f64> = None;
     for p1 in left_paths {
@@ -94,11 +166,15 @@ fn min_distance_bezpath(one: &BezPath, other: &BezPath) -> f64 {
             (PathSeg::Line(l1), PathSeg::Line(l2)) => line_line_dist(l1, l2),
             (PathSeg::Line(l1), PathSeg::Quad(c2)) => line_curve_dist(l1, c2),
             (PathSeg::Quad(c1), PathSeg::Line(l2)) => line_curve_dist(l2, c1),
-            (PathSeg::Quad(_c1), PathSeg::Quad(_c2)) => s1.min_dist(s2, 0.5).distance,
-            _ => {
-                debug("Unusual configuration");
-                0.0
-            }
+            // Cubics (and any other curve/curve pairing) don't have a
+            // bespoke sampler, so fall back to kurbo's own nearest-point
+            // search, which already handles `Cubic` correctly.
+            (PathSeg::Quad(_), PathSeg::Quad(_))
+            | (PathSeg::Line(_), PathSeg::Cubic(_))
+            | (PathSeg::Cubic(_), PathSeg::Line(_))
+            | (PathSeg::Quad(_), PathSeg::Cubic(_))
+            | (PathSeg::Cubic(_), PathSeg::Quad(_))
+            | (PathSeg::Cubic(_), PathSeg::Cubic(_)) => s1.min_dist(s2, 0.5).distance,
         }
     } else {
         f64::MAX
@@ -1,6 +1,8 @@
-use harfbuzz_wasm::{Buffer, BufferItem, CGlyphInfo, CGlyphPosition, Font};
+use gulzar::dist::{_determine_kern, path_distance};
+use harfbuzz_wasm::{Buffer, BufferItem, CGlyphInfo, CGlyphPosition, Font, GlyphOutlineSegment};
 use itertools::Itertools;
 use kurbo::{Affine, BezPath, PathEl, PathSeg, Rect};
+use std::collections::HashMap;
 
 // This is the standard glyph representation but with a few more
 // handy fields.
@@ -109,7 +111,23 @@ impl GulzarGlyph {
         paths
     }
 
-    // Terribly inefficient collision detector
+    // Fill in `paths` straight from the font, so a shaper doesn't need
+    // to extract outlines itself before calling `collides` or the
+    // kerning routines.
+    pub fn load_paths(&mut self, font: &Font) {
+        self.paths = bezpaths_from_outline(&font.font_copy_glyph_outline(self.codepoint));
+    }
+
+    // Flatten this glyph's positioned paths into a segment index. A caller
+    // can keep the result and run `GlyphSegments::intersects` against
+    // several neighbors without re-flattening the same outline; `collides`
+    // itself builds a fresh index for both glyphs on every call (see
+    // `collides_cached` for the reusing form).
+    pub fn segment_index(&self, font: &Font) -> GlyphSegments {
+        let (sx, _sy) = font.get_scale();
+        GlyphSegments::build(&self.positioned_paths(), 50.0 * (sx as f64))
+    }
+
     pub fn collides(&self, other: &GulzarGlyph, font: &Font) -> bool {
         // If the bounding boxes don't intersect, we can't collide.
         if self
@@ -120,44 +138,334 @@ impl GulzarGlyph {
         {
             return false;
         }
+        self.segment_index(font)
+            .intersects(&other.segment_index(font))
+    }
+
+    // Same as `segment_index`, but via `cache` so a glyph whose position
+    // hasn't moved since the last lookup this run (or last run) is read
+    // back instead of re-flattened.
+    pub fn segment_index_cached(
+        &self,
+        font: &Font,
+        cache: &mut PositionedPathCache,
+    ) -> GlyphSegments {
+        cache.segments(self, font)
+    }
+
+    pub fn collides_cached(
+        &self,
+        other: &GulzarGlyph,
+        font: &Font,
+        cache: &mut PositionedPathCache,
+    ) -> bool {
+        if self
+            .bounding_box(font)
+            .intersect(other.bounding_box(font))
+            .area()
+            == 0.0
+        {
+            return false;
+        }
+        self.segment_index_cached(font, cache)
+            .intersects(&other.segment_index_cached(font, cache))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PositionedPathKey {
+    codepoint: u32,
+    x_total_advance: i32,
+    x_offset: i32,
+    y_offset: i32,
+}
+
+impl PositionedPathKey {
+    fn of(glyph: &GulzarGlyph) -> Self {
+        Self {
+            codepoint: glyph.codepoint,
+            x_total_advance: glyph.x_total_advance,
+            x_offset: glyph.x_offset,
+            y_offset: glyph.y_offset,
+        }
+    }
+}
+
+// See `gulzar::dist::KernCache` for the double-buffering rationale. This
+// caches the flattened `GlyphSegments` rather than the pre-flatten
+// `positioned_paths`, since the flatten is the expensive step `collides`
+// repeats for the same glyph.
+#[derive(Debug, Default)]
+pub struct PositionedPathCache {
+    current: HashMap<PositionedPathKey, GlyphSegments>,
+    previous: HashMap<PositionedPathKey, GlyphSegments>,
+}
+
+impl PositionedPathCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn segments(&mut self, glyph: &GulzarGlyph, font: &Font) -> GlyphSegments {
+        let key = PositionedPathKey::of(glyph);
+        if let Some(segments) = self.current.get(&key) {
+            return segments.clone();
+        }
+        if let Some(segments) = self.previous.remove(&key) {
+            self.current.insert(key, segments.clone());
+            return segments;
+        }
         let (sx, _sy) = font.get_scale();
+        let segments = GlyphSegments::build(&glyph.positioned_paths(), 50.0 * (sx as f64));
+        self.current.insert(key, segments.clone());
+        segments
+    }
 
-        let my_paths = self.positioned_paths();
-        let their_paths = other.positioned_paths();
-        // We could do line sweep or something here, but proof of concept...
-        for p1 in my_paths {
-            for p2 in &their_paths {
-                if intersects(&p1, p2, 50.0 * (sx as f64)) {
-                    return true;
+    pub fn finish_run(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+pub type GulzarBuffer = Buffer<GulzarGlyph>;
+
+// Batched form of `GulzarGlyph::load_paths`, run once up front so the
+// rest of the shaper can assume every glyph already has its outline.
+pub fn load_all_paths(buffer: &mut GulzarBuffer, font: &Font) {
+    for glyph in buffer.glyphs.iter_mut() {
+        glyph.load_paths(font);
+    }
+}
+
+// Nudge every dot (nuqta) mark in `buffer` away from any neighbor closer
+// than `clearance`, with the same kind of iterative correction loop
+// `_determine_kern` runs on the horizontal axis, but working on
+// `y_offset` (at most 10 steps) and then on `x_offset` (at most 10 more)
+// for whatever is still too close. Run this after kerning/positioning so
+// the marks settle against their final neighbors.
+pub fn reposition_dots(
+    buffer: &mut GulzarBuffer,
+    font: &Font,
+    clearance: f32,
+    cache: &mut PositionedPathCache,
+) {
+    for i in 0..buffer.glyphs.len() {
+        let glyph = &buffer.glyphs[i];
+        if !(glyph.is_dot_below() || glyph.is_dot_above()) {
+            continue;
+        }
+        // Dots below a base push further down; dots above push further up.
+        let direction: f32 = if glyph.is_dot_below() { -1.0 } else { 1.0 };
+
+        let mut iterations = 0;
+        let mut min_distance = -9999.0_f32;
+        while iterations < 10 && min_distance < clearance {
+            let distances = close_neighbor_distances(buffer, font, cache, i, clearance);
+            if distances.is_empty() {
+                break;
+            }
+            min_distance = distances.into_iter().fold(f32::MAX, f32::min);
+            let adjustment = (clearance - min_distance).max(0.0);
+            buffer.glyphs[i].y_offset += (direction * adjustment) as i32;
+            iterations += 1;
+        }
+
+        // Vertical room ran out (e.g. wedged between two bases); nudge
+        // horizontally away from whatever's still too close, re-checking
+        // after each step the same way the vertical loop does.
+        let mut h_iterations = 0;
+        while h_iterations < 10 {
+            let Some(j) = (0..buffer.glyphs.len()).find(|&j| {
+                j != i
+                    && neighbor_distance(&buffer.glyphs[i], &buffer.glyphs[j], font, cache)
+                        .is_some_and(|d| d < clearance)
+            }) else {
+                break;
+            };
+            let my_center = buffer.glyphs[i].bounding_box(font).center().x;
+            let their_center = buffer.glyphs[j].bounding_box(font).center().x;
+            let x_direction = if my_center < their_center { -1.0 } else { 1.0 };
+            buffer.glyphs[i].x_offset += (x_direction * clearance / 4.0) as i32;
+            h_iterations += 1;
+        }
+    }
+}
+
+// Distances (in font units) from glyph `i` to every neighbor within
+// `clearance`.
+fn close_neighbor_distances(
+    buffer: &GulzarBuffer,
+    font: &Font,
+    cache: &mut PositionedPathCache,
+    i: usize,
+    clearance: f32,
+) -> Vec<f32> {
+    (0..buffer.glyphs.len())
+        .filter(|&j| j != i)
+        .filter_map(|j| neighbor_distance(&buffer.glyphs[i], &buffer.glyphs[j], font, cache))
+        .filter(|&d| d < clearance)
+        .collect()
+}
+
+// An overlapping pair is trivially closer than any positive clearance,
+// and `collides_cached` can tell us that cheaply via the cached segment
+// index; anything else falls back to `path_distance` between the curves.
+fn neighbor_distance(
+    mine: &GulzarGlyph,
+    other: &GulzarGlyph,
+    font: &Font,
+    cache: &mut PositionedPathCache,
+) -> Option<f32> {
+    if mine.collides_cached(other, font, cache) {
+        return Some(0.0);
+    }
+    path_distance(&mine.positioned_paths(), &other.positioned_paths())
+}
+
+// Walk `buffer` and flag every glyph that sits within the horizontal
+// extent of a bari ye's bounding box as part of that bari ye's run, so
+// the rest of the shaper can treat the sweep and its subtended letters
+// as one region instead of testing each rectangular bounding box in
+// isolation.
+pub fn mark_bari_ye_runs(buffer: &mut GulzarBuffer, font: &Font) {
+    let bari_ye_extents: Vec<(f64, f64)> = buffer
+        .glyphs
+        .iter()
+        .filter(|g| g.is_bari_ye())
+        .map(|g| {
+            let bbox = g.bounding_box(font);
+            (bbox.x0, bbox.x1)
+        })
+        .collect();
+
+    for glyph in buffer.glyphs.iter_mut() {
+        let bbox = glyph.bounding_box(font);
+        glyph.in_bari_ye = bari_ye_extents
+            .iter()
+            .any(|&(x0, x1)| bbox.x0 < x1 && bbox.x1 > x0);
+    }
+}
+
+// Collision test for members of a bari ye run against the bari ye's
+// curved outline: true when `path_distance` between the two outlines is
+// below `clearance`. No bounding-box pre-check is made, since the bari
+// ye's own bounding box spans the whole sweep and overlaps every
+// subtended letter and dot regardless of where the curve actually sits.
+pub fn collides_with_bari_ye(mark: &GulzarGlyph, bari_ye: &GulzarGlyph, clearance: f32) -> bool {
+    path_distance(&mark.positioned_paths(), &bari_ye.positioned_paths())
+        .map(|d| d < clearance)
+        .unwrap_or(false)
+}
+
+// Kern a bari ye run member against the bari ye's actual curved outline,
+// using the same iterative correction loop `_determine_kern` already runs
+// for ordinary glyph pairs.
+pub fn kern_against_bari_ye(
+    member: &GulzarGlyph,
+    bari_ye: &GulzarGlyph,
+    target_distance: f32,
+    max_tuck: f32,
+    scale_factor: f32,
+) -> f32 {
+    _determine_kern(
+        &bari_ye.positioned_paths(),
+        &member.positioned_paths(),
+        target_distance,
+        max_tuck,
+        scale_factor,
+    )
+}
+
+// `font_copy_glyph_outline` hands back a flat list of draw-callback-style
+// segments (one `MoveTo` per contour); turn that into one `BezPath` per
+// contour.
+fn bezpaths_from_outline(segments: &[GlyphOutlineSegment]) -> Vec<BezPath> {
+    let mut paths = vec![];
+    let mut current = BezPath::new();
+    for segment in segments {
+        match *segment {
+            GlyphOutlineSegment::MoveTo(x, y) => {
+                if !current.elements().is_empty() {
+                    paths.push(current);
                 }
+                current = BezPath::new();
+                current.move_to((x as f64, y as f64));
             }
+            GlyphOutlineSegment::LineTo(x, y) => current.line_to((x as f64, y as f64)),
+            GlyphOutlineSegment::QuadTo(cx, cy, x, y) => {
+                current.quad_to((cx as f64, cy as f64), (x as f64, y as f64))
+            }
+            GlyphOutlineSegment::CubicTo(c1x, c1y, c2x, c2y, x, y) => current.curve_to(
+                (c1x as f64, c1y as f64),
+                (c2x as f64, c2y as f64),
+                (x as f64, y as f64),
+            ),
+            GlyphOutlineSegment::ClosePath => current.close_path(),
         }
-        false
     }
+    if !current.elements().is_empty() {
+        paths.push(current);
+    }
+    paths
 }
-pub type GulzarBuffer = Buffer<GulzarGlyph>;
 
-fn intersects(b1: &BezPath, b2: &BezPath, scale: f64) -> bool {
-    let mut pts1 = vec![];
-    let mut pts2 = vec![];
-    b1.flatten(scale, |el| match el {
-        PathEl::MoveTo(a) => pts1.push(a),
-        PathEl::LineTo(a) => pts1.push(a),
-        _ => {}
-    });
-    b2.flatten(scale, |el| match el {
-        PathEl::MoveTo(a) => pts2.push(a),
-        PathEl::LineTo(a) => pts2.push(a),
-        _ => {}
-    });
-    for (&la1, &la2) in pts1.iter().circular_tuple_windows() {
-        for (&lb1, &lb2) in pts2.iter().circular_tuple_windows() {
-            let seg1 = PathSeg::Line(kurbo::Line::new(la1, la2));
-            let seg2 = kurbo::Line::new(lb1, lb2);
-            if !seg1.intersect_line(seg2).is_empty() {
-                return true;
+// A glyph's positioned outline, flattened to line segments and indexed
+// by x-extent, so `intersects` only has to run the exact line/line test
+// on the pairs whose bounding boxes can plausibly overlap.
+#[derive(Debug, Clone)]
+pub struct GlyphSegments {
+    // Sorted by `bbox.x0` ascending.
+    segments: Vec<(kurbo::Line, Rect)>,
+}
+
+impl GlyphSegments {
+    pub fn build(paths: &[BezPath], scale: f64) -> Self {
+        let mut segments = vec![];
+        for path in paths {
+            let mut pts = vec![];
+            path.flatten(scale, |el| pts.extend(flattened_endpoint(el)));
+            for (&a, &b) in pts.iter().circular_tuple_windows() {
+                segments.push((kurbo::Line::new(a, b), Rect::from_points(a, b)));
+            }
+        }
+        segments.sort_by(|(_, a), (_, b)| a.x0.partial_cmp(&b.x0).unwrap());
+        Self { segments }
+    }
+
+    pub fn intersects(&self, other: &GlyphSegments) -> bool {
+        for (line, bbox) in &self.segments {
+            // `other.segments` is sorted by x0, so nothing past this
+            // point can have an x-extent overlapping `bbox` either.
+            let end = other
+                .segments
+                .partition_point(|(_, other_bbox)| other_bbox.x0 <= bbox.x1);
+            for (other_line, other_bbox) in &other.segments[..end] {
+                // `Rect::intersect(..).area() == 0.0` is true for *any*
+                // overlap of an axis-aligned (zero-width or zero-height)
+                // segment box, not just a miss, so check the per-axis
+                // intervals directly instead.
+                let x_overlaps = bbox.x0 <= other_bbox.x1 && bbox.x1 >= other_bbox.x0;
+                let y_overlaps = bbox.y0 <= other_bbox.y1 && bbox.y1 >= other_bbox.y0;
+                if !x_overlaps || !y_overlaps {
+                    continue;
+                }
+                if !PathSeg::Line(*line).intersect_line(*other_line).is_empty() {
+                    return true;
+                }
             }
         }
+        false
+    }
+}
+
+// `flatten` reduces every `PathEl` to line segments and only reports
+// `MoveTo`, `LineTo` and `ClosePath`; the curve variants are still matched
+// explicitly (rather than a wildcard) and yield their endpoint, so no
+// element kind is silently dropped.
+fn flattened_endpoint(el: PathEl) -> Option<kurbo::Point> {
+    match el {
+        PathEl::MoveTo(p) => Some(p),
+        PathEl::LineTo(p) => Some(p),
+        PathEl::QuadTo(_, p) => Some(p),
+        PathEl::CurveTo(_, _, p) => Some(p),
+        PathEl::ClosePath => None,
     }
-    false
 }